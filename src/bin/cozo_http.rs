@@ -1,10 +1,25 @@
 use actix_cors::Cors;
-use actix_web::{post, web, App, HttpResponse, HttpServer, Responder};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::{from_fn, Next};
+use actix_web::{get, post, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
 use clap::Parser;
-use cozo::{AttrTxItem, Db};
+use cozo::{AttrTxItem, Db, Session, TableInfo, Transaction, Typing};
 use cozorocks::DbBuilder;
+use futures_util::stream;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::fmt::{Debug, Display, Formatter};
+use std::fs;
 use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
 
 type Result<T> = std::result::Result<T, RespError>;
 
@@ -50,10 +65,187 @@ struct Args {
     /// Temporary database, i.e. will be deleted when the program exits
     #[clap(short, long, default_value_t = false, action)]
     temp: bool,
+
+    /// Bearer token required on the `Authorization`/`x-cozo-auth` header for
+    /// all transaction/query endpoints. If neither this nor `token-file` is
+    /// given, a random token is generated for non-loopback binds.
+    #[clap(long)]
+    token: Option<String>,
+
+    /// Path to a file containing the bearer token, read once at startup
+    #[clap(long)]
+    token_file: Option<String>,
+
+    /// Address to bind the admin endpoints (`/metrics`, `/health`) to, kept
+    /// separate from the query port so it can be firewalled independently
+    #[clap(long, default_value_t = String::from("127.0.0.1"))]
+    admin_bind: String,
+
+    /// Port for the admin endpoints
+    #[clap(long, default_value_t = 9071)]
+    admin_port: u16,
+}
+
+fn is_loopback(bind: &str) -> bool {
+    matches!(bind, "127.0.0.1" | "::1" | "localhost")
+}
+
+/// Resolves the effective auth token from the CLI args, generating one for
+/// non-loopback binds that were not given an explicit token.
+fn resolve_auth_token(args: &Args) -> anyhow::Result<Option<String>> {
+    if let Some(token) = &args.token {
+        return Ok(Some(token.clone()));
+    }
+    if let Some(path) = &args.token_file {
+        let token = fs::read_to_string(path)?.trim().to_string();
+        return Ok(Some(token));
+    }
+    if is_loopback(&args.bind) {
+        return Ok(None);
+    }
+    let token = Uuid::new_v4().to_string();
+    eprintln!(
+        "WARNING: no --token/--token-file given for non-loopback bind {}; \
+         generated a one-off token for this run: {}",
+        args.bind, token
+    );
+    Ok(Some(token))
+}
+
+/// Prometheus counters/gauges/histograms tracking server activity, exposed
+/// in text format on the admin `/metrics` endpoint.
+struct Metrics {
+    registry: Registry,
+    queries_total: IntCounter,
+    transactions_total: IntCounter,
+    errors_total: IntCounterVec,
+    open_transactions: IntGauge,
+    request_latency: HistogramVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+        let queries_total =
+            IntCounter::new("cozo_queries_total", "Total number of queries served").unwrap();
+        let transactions_total = IntCounter::new(
+            "cozo_transactions_total",
+            "Total number of transactions committed",
+        )
+        .unwrap();
+        let errors_total = IntCounterVec::new(
+            Opts::new(
+                "cozo_errors_total",
+                "Total number of request errors by kind",
+            ),
+            &["kind"],
+        )
+        .unwrap();
+        let open_transactions = IntGauge::new(
+            "cozo_open_transactions",
+            "Number of currently open multi-statement transactions",
+        )
+        .unwrap();
+        let request_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "cozo_request_latency_seconds",
+                "Request latency in seconds by endpoint",
+            ),
+            &["endpoint"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(queries_total.clone())).unwrap();
+        registry
+            .register(Box::new(transactions_total.clone()))
+            .unwrap();
+        registry.register(Box::new(errors_total.clone())).unwrap();
+        registry
+            .register(Box::new(open_transactions.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(request_latency.clone()))
+            .unwrap();
+
+        Metrics {
+            registry,
+            queries_total,
+            transactions_total,
+            errors_total,
+            open_transactions,
+            request_latency,
+        }
+    }
+
+    fn observe_error(&self, kind: &str) {
+        self.errors_total.with_label_values(&[kind]).inc();
+    }
+
+    fn observe_latency(&self, endpoint: &str, elapsed: Duration) {
+        self.request_latency
+            .with_label_values(&[endpoint])
+            .observe(elapsed.as_secs_f64());
+    }
+}
+
+type TxId = String;
+
+/// An in-flight write transaction kept open across several HTTP requests,
+/// plus the bookkeeping needed to reap it if the caller never comes back.
+struct OpenTx {
+    tx: Transaction,
+    last_active: Instant,
 }
 
+/// Open transactions idle for longer than this are aborted and dropped by
+/// the reaper task, so an abandoned `/tx/begin` doesn't pin storage forever.
+const TX_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+const TX_REAPER_INTERVAL: Duration = Duration::from_secs(30);
+
 struct AppStateWithDb {
     db: Db,
+    /// Schema-info cache shared by every endpoint that resolves `TableInfo`
+    /// by table name, so a bulk insert or schema lookup doesn't re-run
+    /// `resolve`/`table_data` against storage for tables it already knows
+    /// about. Schema-mutating commits invalidate it below.
+    schema_session: Mutex<Session<'static>>,
+    auth_token: Option<String>,
+    open_txs: Arc<Mutex<HashMap<TxId, OpenTx>>>,
+    metrics: Arc<Metrics>,
+}
+
+fn supplied_token(req: &ServiceRequest) -> Option<String> {
+    req.headers()
+        .get("x-cozo-auth")
+        .or_else(|| req.headers().get(actix_web::http::header::AUTHORIZATION))
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim_start_matches("Bearer ").to_string())
+}
+
+/// Compares the supplied token against the expected one in constant time,
+/// so a timing side channel can't be used to guess the token byte-by-byte.
+fn tokens_match(supplied: &str, expected: &str) -> bool {
+    supplied.len() == expected.len() && bool::from(supplied.as_bytes().ct_eq(expected.as_bytes()))
+}
+
+async fn require_auth(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> std::result::Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let expected = req
+        .app_data::<web::Data<AppStateWithDb>>()
+        .and_then(|data| data.auth_token.clone());
+    if let Some(expected) = expected {
+        let ok = supplied_token(&req)
+            .map(|supplied| tokens_match(&supplied, &expected))
+            .unwrap_or(false);
+        if !ok {
+            return Err(actix_web::error::ErrorUnauthorized(
+                "missing or invalid auth token",
+            ));
+        }
+    }
+    next.call(req).await
 }
 
 #[post("/tx")]
@@ -70,20 +262,360 @@ async fn transact_attr(
     body: web::Json<serde_json::Value>,
     data: web::Data<AppStateWithDb>,
 ) -> Result<impl Responder> {
-    let (attrs, comment) = AttrTxItem::parse_request(&body)?;
-    let mut tx = data.db.transact_write()?;
-    tx.tx_attrs(attrs)?;
-    tx.commit_tx(&comment, false)?;
-    Ok(HttpResponse::Ok().body("transact-attr success"))
+    let started = Instant::now();
+    let result = (|| -> anyhow::Result<()> {
+        let (attrs, comment) = AttrTxItem::parse_request(&body)?;
+        let mut tx = data.db.transact_write()?;
+        {
+            let session = data.schema_session.lock().unwrap();
+            tx.tx_attrs(attrs, &session)?;
+        }
+        tx.commit_tx(&comment, false)?;
+        // Attribute transactions can redefine or delete any table, and
+        // don't tell us which by name, so drop the whole schema cache.
+        data.schema_session
+            .lock()
+            .unwrap()
+            .invalidate_all_table_info();
+        Ok(())
+    })();
+    data.metrics.observe_latency("txa", started.elapsed());
+    match result {
+        Ok(()) => {
+            data.metrics.transactions_total.inc();
+            Ok(HttpResponse::Ok().body("transact-attr success"))
+        }
+        Err(err) => {
+            data.metrics.observe_error("txa");
+            Err(err.into())
+        }
+    }
+}
+
+#[post("/tx/begin")]
+async fn tx_begin(data: web::Data<AppStateWithDb>) -> Result<impl Responder> {
+    let started = Instant::now();
+    let tx = match data.db.transact_write() {
+        Ok(tx) => tx,
+        Err(err) => {
+            data.metrics.observe_error("tx_begin");
+            return Err(err.into());
+        }
+    };
+    let tx_id = Uuid::new_v4().to_string();
+    data.open_txs.lock().unwrap().insert(
+        tx_id.clone(),
+        OpenTx {
+            tx,
+            last_active: Instant::now(),
+        },
+    );
+    data.metrics.open_transactions.inc();
+    data.metrics.observe_latency("tx_begin", started.elapsed());
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "tx_id": tx_id })))
+}
+
+fn take_open_tx(
+    data: &web::Data<AppStateWithDb>,
+    tx_id: &str,
+) -> std::result::Result<OpenTx, anyhow::Error> {
+    data.open_txs
+        .lock()
+        .unwrap()
+        .remove(tx_id)
+        .ok_or_else(|| anyhow::anyhow!("no open transaction with id '{}'", tx_id))
+}
+
+#[post("/tx/{id}")]
+async fn tx_run(
+    path: web::Path<String>,
+    body: web::Json<serde_json::Value>,
+    data: web::Data<AppStateWithDb>,
+) -> Result<impl Responder> {
+    let tx_id = path.into_inner();
+    let started = Instant::now();
+    let result = (|| -> anyhow::Result<()> {
+        let (attrs, _comment) = AttrTxItem::parse_request(&body)?;
+        let mut open_txs = data.open_txs.lock().unwrap();
+        let open = open_txs
+            .get_mut(&tx_id)
+            .ok_or_else(|| anyhow::anyhow!("no open transaction with id '{}'", tx_id))?;
+        {
+            let session = data.schema_session.lock().unwrap();
+            open.tx.tx_attrs(attrs, &session)?;
+        }
+        open.last_active = Instant::now();
+        Ok(())
+    })();
+    data.metrics.observe_latency("tx_run", started.elapsed());
+    match result {
+        Ok(()) => Ok(HttpResponse::Ok().body("statement applied")),
+        Err(err) => {
+            data.metrics.observe_error("tx_run");
+            Err(err.into())
+        }
+    }
+}
+
+#[post("/tx/{id}/commit")]
+async fn tx_commit(
+    path: web::Path<String>,
+    data: web::Data<AppStateWithDb>,
+) -> Result<impl Responder> {
+    let tx_id = path.into_inner();
+    let mut open = take_open_tx(&data, &tx_id)?;
+    data.metrics.open_transactions.dec();
+    if let Err(err) = open.tx.commit_tx("", false) {
+        data.metrics.observe_error("tx_commit");
+        return Err(err.into());
+    }
+    data.metrics.transactions_total.inc();
+    // A committed transaction may have redefined or deleted tables via
+    // tx_attrs, and we don't track which by name, so drop the whole cache.
+    data.schema_session
+        .lock()
+        .unwrap()
+        .invalidate_all_table_info();
+    Ok(HttpResponse::Ok().body("committed"))
+}
+
+#[post("/tx/{id}/abort")]
+async fn tx_abort(
+    path: web::Path<String>,
+    data: web::Data<AppStateWithDb>,
+) -> Result<impl Responder> {
+    let tx_id = path.into_inner();
+    let started = Instant::now();
+    if let Err(err) = take_open_tx(&data, &tx_id) {
+        data.metrics.observe_error("tx_abort");
+        return Err(err.into());
+    }
+    data.metrics.open_transactions.dec();
+    data.metrics.observe_latency("tx_abort", started.elapsed());
+    Ok(HttpResponse::Ok().body("aborted"))
+}
+
+/// Periodically sweeps transactions that have been idle for longer than
+/// `TX_IDLE_TIMEOUT`, so a client that opened a transaction and disappeared
+/// doesn't hold it (and the storage it touches) open forever.
+fn spawn_tx_reaper(open_txs: Arc<Mutex<HashMap<TxId, OpenTx>>>, metrics: Arc<Metrics>) {
+    actix_web::rt::spawn(async move {
+        loop {
+            actix_web::rt::time::sleep(TX_REAPER_INTERVAL).await;
+            let mut open_txs = open_txs.lock().unwrap();
+            let before = open_txs.len();
+            open_txs.retain(|_, open| open.last_active.elapsed() < TX_IDLE_TIMEOUT);
+            let reaped = before - open_txs.len();
+            if reaped > 0 {
+                metrics.open_transactions.sub(reaped as i64);
+            }
+        }
+    });
+}
+
+fn typing_pairs_to_json(pairs: &[(String, Typing)]) -> serde_json::Value {
+    serde_json::Value::Object(
+        pairs
+            .iter()
+            .map(|(k, t)| (k.clone(), serde_json::Value::String(format!("{:?}", t))))
+            .collect(),
+    )
+}
+
+fn table_info_to_json(info: &TableInfo) -> serde_json::Value {
+    serde_json::json!({
+        "kind": format!("{:?}", info.kind),
+        "table_id": format!("{:?}", info.table_id),
+        "src_table_id": format!("{:?}", info.src_table_id),
+        "dst_table_id": format!("{:?}", info.dst_table_id),
+        "data_keys": info.data_keys,
+        "key_typing": typing_pairs_to_json(&info.key_typing),
+        "val_typing": typing_pairs_to_json(&info.val_typing),
+        "src_key_typing": typing_pairs_to_json(&info.src_key_typing),
+        "dst_key_typing": typing_pairs_to_json(&info.dst_key_typing),
+        "associates": info.associates.iter().map(table_info_to_json).collect::<Vec<_>>(),
+    })
+}
+
+#[get("/schema/{table}")]
+async fn schema_table(
+    path: web::Path<String>,
+    data: web::Data<AppStateWithDb>,
+) -> Result<impl Responder> {
+    let table = path.into_inner();
+    let started = Instant::now();
+    let result = {
+        let session = data.schema_session.lock().unwrap();
+        session.get_table_info_cached(&table)
+    };
+    data.metrics
+        .observe_latency("schema_table", started.elapsed());
+    match result {
+        Ok(info) => Ok(HttpResponse::Ok().json(table_info_to_json(&info))),
+        Err(err) => {
+            data.metrics.observe_error("schema_table");
+            Err(err.into())
+        }
+    }
+}
+
+#[get("/schema")]
+async fn schema_list(data: web::Data<AppStateWithDb>) -> Result<impl Responder> {
+    let started = Instant::now();
+    let result = {
+        let session = data.schema_session.lock().unwrap();
+        session.all_table_names()
+    };
+    data.metrics
+        .observe_latency("schema_list", started.elapsed());
+    match result {
+        Ok(names) => Ok(HttpResponse::Ok().json(names)),
+        Err(err) => {
+            data.metrics.observe_error("schema_list");
+            Err(err.into())
+        }
+    }
+}
+
+/// Query results are chunked into SSE events of this many rows, so a single
+/// big scan doesn't have to be buffered whole before the client sees
+/// anything.
+const SSE_CHUNK_ROWS: usize = 100;
+
+#[derive(Deserialize)]
+struct QueryParams {
+    #[serde(default)]
+    stream: bool,
+}
+
+fn wants_sse(req: &HttpRequest, params: &QueryParams) -> bool {
+    params.stream
+        || req
+            .headers()
+            .get(actix_web::http::header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.contains("text/event-stream"))
+            .unwrap_or(false)
+}
+
+/// Bodies smaller than this aren't worth the CPU cost of compressing, so the
+/// gate leaves them as `identity` and lets `Compress` skip them.
+const MIN_COMPRESS_BYTES: u64 = 256;
+
+async fn gate_small_bodies(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> std::result::Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let res = next.call(req).await?;
+    let skip_compression = matches!(
+        res.response().body().size(),
+        actix_web::body::BodySize::Sized(len) if len < MIN_COMPRESS_BYTES
+    );
+    Ok(res.map_body(move |head, body| {
+        if skip_compression {
+            head.headers_mut().insert(
+                actix_web::http::header::CONTENT_ENCODING,
+                actix_web::http::header::HeaderValue::from_static("identity"),
+            );
+        }
+        body
+    }))
+}
+
+fn sse_event(event: &str, data: &serde_json::Value) -> actix_web::web::Bytes {
+    actix_web::web::Bytes::from(format!(
+        "event: {}\ndata: {}\n\n",
+        event,
+        serde_json::to_string(data).unwrap_or_else(|_| "null".to_string())
+    ))
+}
+
+/// Runs `body` against `data.db` on a blocking thread, pushing SSE "chunk"
+/// events to `tx` as rows arrive instead of collecting the whole result set
+/// first. Bounds server memory on big scans, and lets clients start
+/// processing before the query finishes.
+fn run_query_sse(
+    data: web::Data<AppStateWithDb>,
+    body: serde_json::Value,
+    tx: tokio::sync::mpsc::Sender<actix_web::web::Bytes>,
+) {
+    actix_web::rt::task::spawn_blocking(move || {
+        let started = Instant::now();
+        let mut buf = Vec::with_capacity(SSE_CHUNK_ROWS);
+        let result = data.db.run_query_streaming(&body, &mut |row| {
+            buf.push(row);
+            if buf.len() >= SSE_CHUNK_ROWS {
+                let chunk = std::mem::replace(&mut buf, Vec::with_capacity(SSE_CHUNK_ROWS));
+                tx.blocking_send(sse_event("chunk", &serde_json::json!(chunk)))
+                    .map_err(|_| anyhow::anyhow!("client disconnected"))?;
+            }
+            Ok(())
+        });
+        data.metrics.observe_latency("q", started.elapsed());
+        let final_event = match result {
+            Ok(()) => {
+                if !buf.is_empty() {
+                    let _ = tx.blocking_send(sse_event("chunk", &serde_json::json!(buf)));
+                }
+                data.metrics.queries_total.inc();
+                sse_event("done", &serde_json::json!({}))
+            }
+            Err(err) => {
+                data.metrics.observe_error("q");
+                sse_event("error", &serde_json::json!({ "error": err.to_string() }))
+            }
+        };
+        let _ = tx.blocking_send(final_event);
+    });
 }
 
 #[post("/q")]
 async fn query(
+    req: HttpRequest,
     body: web::Json<serde_json::Value>,
+    params: web::Query<QueryParams>,
     data: web::Data<AppStateWithDb>,
-) -> Result<impl Responder> {
-    dbg!(&body, &data.db);
-    Ok(HttpResponse::Ok().body("query"))
+) -> Result<HttpResponse> {
+    if wants_sse(&req, &params) {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        run_query_sse(data, body.into_inner(), tx);
+        return Ok(HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .streaming(stream::poll_fn(move |cx| {
+                rx.poll_recv(cx).map(|o| o.map(Ok::<_, actix_web::Error>))
+            })));
+    }
+
+    let started = Instant::now();
+    let query_result = data.db.run_query(&body);
+    data.metrics.observe_latency("q", started.elapsed());
+    match query_result {
+        Ok(rows) => {
+            data.metrics.queries_total.inc();
+            Ok(HttpResponse::Ok().json(rows))
+        }
+        Err(err) => {
+            data.metrics.observe_error("q");
+            Err(err.into())
+        }
+    }
+}
+
+#[get("/metrics")]
+async fn metrics_endpoint(data: web::Data<AppStateWithDb>) -> Result<impl Responder> {
+    let metric_families = data.metrics.registry.gather();
+    let mut buf = Vec::new();
+    TextEncoder::new()
+        .encode(&metric_families, &mut buf)
+        .map_err(anyhow::Error::from)?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(buf))
+}
+
+#[get("/health")]
+async fn health() -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
 }
 
 #[actix_web::main]
@@ -101,23 +633,69 @@ async fn main() -> std::io::Result<()> {
         .create_if_missing(true)
         .destroy_on_exit(args.temp);
     let db = Db::build(builder).unwrap();
+    let auth_token = resolve_auth_token(&args).unwrap();
+    let open_txs = Arc::new(Mutex::new(HashMap::new()));
+    let metrics = Arc::new(Metrics::new());
+    spawn_tx_reaper(open_txs.clone(), metrics.clone());
 
-    let app_state = web::Data::new(AppStateWithDb { db });
+    let app_state = web::Data::new(AppStateWithDb {
+        db,
+        schema_session: Mutex::new(Session::new()),
+        auth_token,
+        open_txs,
+        metrics,
+    });
 
     let addr = (&args.bind as &str, args.port);
     eprintln!("Serving database at {}:{}", addr.0, addr.1);
 
-    HttpServer::new(move || {
-        let cors = Cors::permissive();
+    let main_server = {
+        let app_state = app_state.clone();
+        HttpServer::new(move || {
+            let cors = Cors::permissive();
+
+            App::new()
+                .app_data(app_state.clone())
+                .wrap(cors)
+                .wrap(from_fn(require_auth))
+                .wrap(from_fn(gate_small_bodies))
+                .wrap(actix_web::middleware::Compress::default())
+                .service(query)
+                .service(transact)
+                .service(transact_attr)
+                .service(tx_begin)
+                .service(tx_run)
+                .service(tx_commit)
+                .service(tx_abort)
+                .service(schema_table)
+                .service(schema_list)
+        })
+        .bind(addr)?
+        .run()
+    };
+
+    let admin_addr = (&args.admin_bind as &str, args.admin_port);
+    if !is_loopback(&args.admin_bind) {
+        eprintln!(
+            "WARNING: admin endpoints (/metrics, /health) are bound to non-loopback \
+             address {} with no authentication; firewall this port off from untrusted networks",
+            args.admin_bind
+        );
+    }
+    eprintln!(
+        "Serving admin endpoints at {}:{}",
+        admin_addr.0, admin_addr.1
+    );
 
+    let admin_server = HttpServer::new(move || {
         App::new()
             .app_data(app_state.clone())
-            .wrap(cors)
-            .service(query)
-            .service(transact)
-            .service(transact_attr)
+            .service(metrics_endpoint)
+            .service(health)
     })
-    .bind(addr)?
-    .run()
-    .await
+    .bind(admin_addr)?
+    .run();
+
+    futures_util::try_join!(main_server, admin_server)?;
+    Ok(())
 }
@@ -0,0 +1,27 @@
+use crate::db::table::TableInfo;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+pub struct Session<'a> {
+    pub(crate) table_info_cache: RefCell<BTreeMap<String, Arc<TableInfo>>>,
+    _marker: std::marker::PhantomData<&'a ()>,
+}
+
+impl<'a> Session<'a> {
+    /// A session with a freshly-cleared schema-info cache, for callers
+    /// (e.g. the HTTP layer) that want to hold one alongside a `Db` rather
+    /// than opening one per transaction.
+    pub fn new() -> Self {
+        Session {
+            table_info_cache: RefCell::new(BTreeMap::new()),
+            _marker: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<'a> Default for Session<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -5,6 +5,7 @@ use crate::relation::data::DataKind;
 use crate::relation::typing::Typing;
 use std::collections::HashSet;
 use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
 
 #[derive(Eq, PartialEq, Clone, Copy, Ord, PartialOrd, Hash)]
 pub struct TableId {
@@ -171,33 +172,43 @@ impl<'a> Session<'a> {
                             CozoError::LogicError("Dst id extraction failed".to_string())
                         })?;
                         let dst_table_id = TableId::new(dst_in_root, dst_id);
-                        let src = self.table_data(src_id, src_in_root)?.ok_or_else(|| {
-                            CozoError::LogicError("Getting src failed".to_string())
-                        })?;
-                        let src_key = Typing::try_from(
-                            src.get_text(2)
-                                .ok_or_else(|| {
-                                    CozoError::BadDataFormat(tpl.data.as_ref().to_vec())
-                                })?
-                                .as_ref(),
-                        )?
-                        .extract_named_tuple()
-                        .ok_or_else(|| CozoError::LogicError("Corrupt data".to_string()))?;
-                        let src_key_typing = src_key.into_iter().collect();
+                        let src_key_typing = match self.cached_key_typing(src_table_id) {
+                            Some(cached) => cached,
+                            None => {
+                                let src = self.table_data(src_id, src_in_root)?.ok_or_else(|| {
+                                    CozoError::LogicError("Getting src failed".to_string())
+                                })?;
+                                let src_key = Typing::try_from(
+                                    src.get_text(2)
+                                        .ok_or_else(|| {
+                                            CozoError::BadDataFormat(tpl.data.as_ref().to_vec())
+                                        })?
+                                        .as_ref(),
+                                )?
+                                .extract_named_tuple()
+                                .ok_or_else(|| CozoError::LogicError("Corrupt data".to_string()))?;
+                                src_key.into_iter().collect()
+                            }
+                        };
 
-                        let dst = self.table_data(dst_id, dst_in_root)?.ok_or_else(|| {
-                            CozoError::LogicError("Getting dst failed".to_string())
-                        })?;
-                        let dst_key = Typing::try_from(
-                            dst.get_text(2)
-                                .ok_or_else(|| {
-                                    CozoError::BadDataFormat(tpl.data.as_ref().to_vec())
-                                })?
-                                .as_ref(),
-                        )?
-                        .extract_named_tuple()
-                        .ok_or_else(|| CozoError::LogicError("Corrupt data".to_string()))?;
-                        let dst_key_typing = dst_key.into_iter().collect();
+                        let dst_key_typing = match self.cached_key_typing(dst_table_id) {
+                            Some(cached) => cached,
+                            None => {
+                                let dst = self.table_data(dst_id, dst_in_root)?.ok_or_else(|| {
+                                    CozoError::LogicError("Getting dst failed".to_string())
+                                })?;
+                                let dst_key = Typing::try_from(
+                                    dst.get_text(2)
+                                        .ok_or_else(|| {
+                                            CozoError::BadDataFormat(tpl.data.as_ref().to_vec())
+                                        })?
+                                        .as_ref(),
+                                )?
+                                .extract_named_tuple()
+                                .ok_or_else(|| CozoError::LogicError("Corrupt data".to_string()))?;
+                                dst_key.into_iter().collect()
+                            }
+                        };
 
                         let in_root = tpl.get_bool(0).ok_or_else(|| {
                             CozoError::LogicError("Cannot extract in root".to_string())
@@ -258,4 +269,59 @@ impl<'a> Session<'a> {
         };
         Ok(table_info)
     }
+
+    /// Looks up the key typing of an already-cached table by id, so that
+    /// edges whose src/dst happen to be cached don't pay for an extra
+    /// `table_data` round-trip just to re-derive typing we already have.
+    fn cached_key_typing(&self, table_id: TableId) -> Option<Vec<(String, Typing)>> {
+        self.table_info_cache
+            .borrow()
+            .values()
+            .find(|info| info.table_id == table_id)
+            .map(|info| info.key_typing.clone())
+    }
+
+    /// Memoized version of `get_table_info`, keyed by resolved table name.
+    /// Bulk mutations resolve the same handful of tables over and over, so
+    /// sharing the built `TableInfo` saves re-running `resolve`/`table_data`
+    /// on every call.
+    pub fn get_table_info_cached(&self, tbl_name: &str) -> Result<Arc<TableInfo>> {
+        if let Some(info) = self.table_info_cache.borrow().get(tbl_name) {
+            return Ok(info.clone());
+        }
+        let info = Arc::new(self.get_table_info(tbl_name)?);
+        self.table_info_cache
+            .borrow_mut()
+            .insert(tbl_name.to_string(), info.clone());
+        Ok(info)
+    }
+
+    /// Drops `tbl_name` from the schema-info cache. Call this whenever a
+    /// table is redefined or removed so subsequent lookups pick up the new
+    /// typing instead of the stale cached one.
+    pub fn invalidate_table_info(&self, tbl_name: &str) {
+        self.table_info_cache.borrow_mut().remove(tbl_name);
+    }
+
+    /// Drops every cached `TableInfo`. Attribute transactions can redefine
+    /// or delete any table without telling us which one by name, so
+    /// schema-mutating commits invalidate the whole cache rather than risk
+    /// serving a stale typing forever.
+    pub fn invalidate_all_table_info(&self) {
+        let names: Vec<String> = self.table_info_cache.borrow().keys().cloned().collect();
+        for name in names {
+            self.invalidate_table_info(&name);
+        }
+    }
+
+    /// Lists every table currently defined, by name. Walks the same
+    /// catalog that `resolve`/`resolve_related_tables` look individual
+    /// tables up in, rather than reusing the per-name cache.
+    pub fn all_table_names(&self) -> Result<Vec<String>> {
+        Ok(self
+            .resolve_all_tables()?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect())
+    }
 }